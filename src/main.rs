@@ -1,68 +1,302 @@
 use std::{
-    fs::OpenOptions,
+    fs::{File, OpenOptions},
     io::{Read, Write},
     net::TcpStream,
     path::Path,
-    time::Instant,
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
+use sha2::{Digest, Sha256};
 use utils::{parse_args, ProgressBar};
 
 const LENGTH: &str = "Content-Length: ";
+const SHA256: &str = "X-Content-SHA256: ";
+const TRANSFER_ENCODING: &str = "Transfer-Encoding: ";
+const AUTHORIZATION: &str = "Authorization: Key ";
+
+/// Starting delay for the reconnect backoff.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+/// Backoff never waits longer than this between attempts.
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// Consecutive failures allowed for a single range before giving up.
+const MAX_CONSECUTIVE_FAILURES: u32 = 10;
 
 fn main() {
     let args = parse_args();
 
-    let connection = TcpStream::connect(&args.address).unwrap();
-    let mut client = Client { connection };
+    let mut client = Client::connect(args.address.clone(), args.key.clone()).unwrap();
 
     let start_time = Instant::now();
-    client.write(&Requests::Full);
 
-    let response = client.wait_response().unwrap();
-    let target_len = response.headers.content_length().unwrap();
-    let mut progress = ProgressBar::new(target_len, response.data().len());
+    let existing_len = std::fs::metadata(&args.output)
+        .map(|m| m.len() as usize)
+        .unwrap_or(0);
+    let mut hasher = Sha256::new();
+    if existing_len > 0 {
+        hasher.update(std::fs::read(&args.output).unwrap());
+        println!("Resuming download, {existing_len} bytes already on disk");
+    }
+    let initial_request = if existing_len > 0 {
+        Requests::Range(RangeRequest::From(existing_len))
+    } else {
+        Requests::Full
+    };
 
-    let mut data = WholeData {
-        current_len: response.data().len(),
-        target_len,
-        data: vec![response],
+    let mut mismatch_failures = 0;
+    let mut mismatch_backoff = INITIAL_BACKOFF;
+    let (response, target_len) = loop {
+        let candidate = request_with_retry(&mut client, initial_request, "the initial handshake");
+        match validate_initial_response(&candidate, existing_len) {
+            Some(total) => break (candidate, total),
+            None => {
+                mismatch_failures += 1;
+                if mismatch_failures >= MAX_CONSECUTIVE_FAILURES {
+                    panic!(
+                        "Giving up after {mismatch_failures} consecutive mismatched initial responses"
+                    );
+                }
+                eprintln!(
+                    "Server's initial response didn't match our offset, retrying in {mismatch_backoff:?}"
+                );
+                thread::sleep(mismatch_backoff + jitter(mismatch_backoff));
+                mismatch_backoff = (mismatch_backoff * 2).min(MAX_BACKOFF);
+            }
+        }
     };
+    let expected_sha256 = response.headers.content_sha256();
+    let mut progress = ProgressBar::new(target_len, existing_len);
+
+    let mut data = WholeData::open(&args.output, target_len, existing_len, expected_sha256, hasher)
+        .unwrap();
+    match data.add(response) {
+        AddOutcome::Done | AddOutcome::Continue => progress.update(data.current_len - existing_len),
+        AddOutcome::Mismatch => unreachable!("validate_initial_response already checked this"),
+    }
 
+    let mut mismatch_failures = 0;
+    let mut mismatch_backoff = INITIAL_BACKOFF;
     while data.current_len != data.target_len {
-        client.reconnect().unwrap();
-        client
-            .write(&Requests::Range(data.current_len, data.target_len))
-            .unwrap();
-        let response = client.wait_response().unwrap();
-        progress.update(response.data().len());
-        if data.add(response) {
-            break;
+        let context = format!("range {}-{}", data.current_len, data.target_len);
+        let request = Requests::Range(RangeRequest::Full(data.current_len, data.target_len));
+        let response = request_with_retry(&mut client, request, &context);
+        let len = response.data().len();
+        match data.add(response) {
+            AddOutcome::Done => {
+                progress.update(len);
+                break;
+            }
+            AddOutcome::Continue => {
+                progress.update(len);
+                mismatch_failures = 0;
+                mismatch_backoff = INITIAL_BACKOFF;
+            }
+            AddOutcome::Mismatch => {
+                mismatch_failures += 1;
+                if mismatch_failures >= MAX_CONSECUTIVE_FAILURES {
+                    panic!(
+                        "Giving up after {mismatch_failures} consecutive range mismatches at offset {}",
+                        data.current_len
+                    );
+                }
+                eprintln!(
+                    "Server returned a range that doesn't match our offset ({}), retrying in {mismatch_backoff:?}",
+                    data.current_len
+                );
+                thread::sleep(mismatch_backoff + jitter(mismatch_backoff));
+                mismatch_backoff = (mismatch_backoff * 2).min(MAX_BACKOFF);
+            }
         }
     }
     progress.done();
 
     println!("Download complete, time: {:?}", start_time.elapsed());
-    data.save(&args.output).unwrap();
     println!("Data written to {:?}", args.output);
 
-    println!(
-        "Please manually compare the SHA-256 hash printed by the server with the downloaded file"
-    );
+    match data.verify() {
+        Verification::Match => println!("SHA-256 integrity check passed"),
+        Verification::Mismatch { expected, got } => {
+            eprintln!(
+                "SHA-256 mismatch: expected {}, got {} — deleting partial output",
+                hex(&expected),
+                hex(&got)
+            );
+            let _ = std::fs::remove_file(&args.output);
+            std::process::exit(1);
+        }
+        Verification::NotProvided => {
+            println!("Server did not provide a digest, skipping integrity check");
+        }
+    }
+}
+
+fn hex(bytes: &[u8; 32]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Sends `request` on a fresh connection, reconnecting with exponential backoff and
+/// jitter on any transport failure. `context` only labels log/panic messages (e.g.
+/// `"range 0-1023"` or `"the initial handshake"`). Gives up after
+/// [`MAX_CONSECUTIVE_FAILURES`] failed attempts.
+fn request_with_retry(client: &mut Client, request: Requests, context: &str) -> Response {
+    let mut backoff = INITIAL_BACKOFF;
+    let mut failures = 0;
+
+    loop {
+        let attempt = (|| -> Result<Response, WriteError> {
+            client.reconnect().ok_or(WriteError::Transport)?;
+            client.write(request)?;
+            client.wait_response().ok_or(WriteError::Transport)
+        })();
+
+        match attempt {
+            Ok(response) => return response,
+            Err(WriteError::AuthRejected) => {
+                panic!("Server rejected the authentication key while fetching {context}")
+            }
+            Err(WriteError::Transport) => {
+                failures += 1;
+                if failures >= MAX_CONSECUTIVE_FAILURES {
+                    panic!("Giving up after {failures} consecutive failures fetching {context}");
+                }
+                eprintln!(
+                    "Transient failure fetching {context} (attempt {failures}), retrying in {backoff:?}"
+                );
+                thread::sleep(backoff + jitter(backoff));
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        }
+    }
+}
+
+/// Checks that the first response of a download starts at `existing_len` and reports a
+/// usable total size, returning `None` if the server's response can't be trusted (so the
+/// caller can retry it like any other transport failure instead of panicking on byte 0).
+fn validate_initial_response(response: &Response, existing_len: usize) -> Option<usize> {
+    let headers = &response.headers;
+    if let Some(code) = headers.status_code()
+        && code != 200
+        && code != 206
+    {
+        return None;
+    }
+    match headers.content_range() {
+        Some((start, end, total))
+            if start == existing_len
+                && end >= start
+                && end < total
+                && response.data().len() == end - start + 1 =>
+        {
+            Some(total)
+        }
+        Some(_) => None,
+        None if existing_len > 0 => None,
+        None => headers.content_length(),
+    }
+}
+
+/// A small random delay (0-250ms) added to backoff to avoid thundering-herd reconnects.
+fn jitter(backoff: Duration) -> Duration {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or(backoff.subsec_nanos());
+    Duration::from_millis((nanos % 250) as u64)
 }
 
 struct Client {
     pub connection: TcpStream,
+    pub address: String,
+    pub key: Option<String>,
+}
+
+/// Why a [`Client::write`] handshake failed to go through.
+enum WriteError {
+    /// The connect/write/flush itself failed.
+    Transport,
+    /// The server rejected the authentication key.
+    AuthRejected,
 }
 
 struct WholeData {
     pub target_len: usize,
     pub current_len: usize,
-    pub data: Vec<Response>,
+    pub expected_sha256: Option<[u8; 32]>,
+    hasher: Sha256,
+    file: File,
+}
+
+/// Outcome of feeding a range [`Response`] into [`WholeData::add`].
+enum AddOutcome {
+    /// The whole file has now been obtained.
+    Done,
+    /// Accepted, but more data remains.
+    Continue,
+    /// The server's `Content-Range`/status didn't match where we expected to be;
+    /// the response was discarded and the same range should be re-requested.
+    Mismatch,
 }
+
+/// Result of comparing the downloaded data against the server-supplied digest.
+enum Verification {
+    Match,
+    Mismatch {
+        expected: [u8; 32],
+        got: [u8; 32],
+    },
+    /// The server did not send an `X-Content-SHA256` header.
+    NotProvided,
+}
+#[derive(Clone, Copy)]
 enum Requests {
     Full,
-    Range(usize, usize),
+    Range(RangeRequest),
+}
+
+/// The three ways a byte range can be requested, mirroring RFC 7233's `Range` header.
+/// `Suffix` isn't issued anywhere today but is modeled alongside the others for
+/// servers that require it.
+#[derive(Clone, Copy)]
+#[allow(dead_code)]
+enum RangeRequest {
+    /// `bytes=n-` — everything from `n` to the end, used when the tail boundary is unknown.
+    From(usize),
+    /// `bytes=n-m` — the closed range `[n, m]`.
+    Full(usize, usize),
+    /// `bytes=-n` — the last `n` bytes.
+    Suffix(usize),
+}
+
+impl RangeRequest {
+    fn to_header_value(self) -> String {
+        match self {
+            Self::From(n) => format!("bytes={n}-"),
+            Self::Full(n, m) => format!("bytes={n}-{m}"),
+            Self::Suffix(n) => format!("bytes=-{n}"),
+        }
+    }
+}
+
+/// A parsed `Content-Range: bytes start-end/total` response header.
+struct ContentRange {
+    pub start: usize,
+    pub end: usize,
+    pub total: usize,
+}
+
+impl ContentRange {
+    const PREFIX: &'static str = "Content-Range: bytes ";
+
+    fn parse(line: &str) -> Option<Self> {
+        let rest = line.strip_prefix(Self::PREFIX)?;
+        let (range, total) = rest.split_once('/')?;
+        let (start, end) = range.split_once('-')?;
+        Some(Self {
+            start: start.trim().parse().ok()?,
+            end: end.trim().parse().ok()?,
+            total: total.trim().parse().ok()?,
+        })
+    }
 }
 
 struct Headers {
@@ -70,72 +304,141 @@ struct Headers {
 }
 
 struct Response {
-    pub full_data: Vec<u8>,
-    pub headers_splitoff: usize,
     pub headers: Headers,
+    body: Vec<u8>,
 }
 
 impl Client {
-    pub fn write(&mut self, request: &Requests) -> Option<()> {
-        let header = request.to_header();
+    pub fn connect(address: String, key: Option<String>) -> Option<Self> {
+        let connection = TcpStream::connect(&address).ok()?;
+        Some(Self {
+            connection,
+            address,
+            key,
+        })
+    }
+
+    /// Sends `request`, including the `Authorization` header when a key is configured.
+    /// Whenever a key is configured, also waits for the server's single-byte
+    /// confirmation (`b'1'` accepted, anything else rejected) before moving on — every
+    /// request runs over its own freshly (re)connected socket, so this confirmation is
+    /// per-connection, not a one-time handshake.
+    pub fn write(&mut self, request: Requests) -> Result<(), WriteError> {
+        let header = request.to_header(self.key.as_deref());
         let data = header.as_bytes();
 
-        self.connection.write_all(data).ok()?;
-        self.connection.flush().ok()?;
-        Some(())
+        self.connection
+            .write_all(data)
+            .map_err(|_| WriteError::Transport)?;
+        self.connection.flush().map_err(|_| WriteError::Transport)?;
+
+        if self.key.is_some() {
+            let mut ack = [0u8; 1];
+            self.connection
+                .read_exact(&mut ack)
+                .map_err(|_| WriteError::Transport)?;
+            if ack[0] != b'1' {
+                return Err(WriteError::AuthRejected);
+            }
+        }
+        Ok(())
     }
 
     pub fn wait_response(&mut self) -> Option<Response> {
         let mut response = Vec::new();
-        self.connection.read_to_end(&mut response).unwrap();
+        self.connection.read_to_end(&mut response).ok()?;
 
         response.try_into().ok()
     }
 
     pub fn reconnect(&mut self) -> Option<()> {
-        self.connection = TcpStream::connect("127.0.0.1:8080").ok()?;
+        self.connection = TcpStream::connect(&self.address).ok()?;
         Some(())
     }
 }
 
 impl WholeData {
-    /// Returns true if the whole data has been obtained
-    pub fn add(&mut self, response: Response) -> bool {
-        self.current_len += response.data().len();
-        self.data.push(response);
-        self.current_len == self.target_len
+    /// Opens `path` for streaming output, appending to whatever is already there
+    /// (`current_len` bytes, for a resumed download).
+    pub fn open<T: AsRef<Path>>(
+        path: T,
+        target_len: usize,
+        current_len: usize,
+        expected_sha256: Option<[u8; 32]>,
+        hasher: Sha256,
+    ) -> Option<Self> {
+        let file = OpenOptions::new().append(true).create(true).open(path).ok()?;
+        Some(Self {
+            target_len,
+            current_len,
+            expected_sha256,
+            hasher,
+            file,
+        })
     }
 
-    pub fn _to_vec(&self) -> Vec<u8> {
-        self.data
-            .iter()
-            .map(|d| d.data())
-            .fold(Vec::with_capacity(self.target_len), |mut a, b| {
-                a.extend_from_slice(b);
-                a
-            })
+    /// Validates, hashes and streams a range response straight to disk, rejecting it
+    /// (without mutating state) if the server's `Content-Range` disagrees with where
+    /// we actually are, is internally inconsistent with the body it sent, or is missing
+    /// entirely when we're partway through the file (e.g. a resumed download, where a
+    /// bare `200 OK` would mean the server ignored our offset and sent the whole file).
+    pub fn add(&mut self, response: Response) -> AddOutcome {
+        match response.headers.content_range() {
+            Some((start, end, total))
+                if start != self.current_len
+                    || total != self.target_len
+                    || end < start
+                    || end >= total
+                    || response.data().len() != end - start + 1 =>
+            {
+                return AddOutcome::Mismatch;
+            }
+            Some(_) => {}
+            None if self.current_len > 0 => return AddOutcome::Mismatch,
+            None => {}
+        }
+        if matches!(response.headers.status_code(), Some(code) if code != 206 && code != 200) {
+            return AddOutcome::Mismatch;
+        }
+
+        self.file.write_all(response.data()).unwrap();
+        self.current_len += response.data().len();
+        self.hasher.update(response.data());
+        if self.current_len == self.target_len {
+            AddOutcome::Done
+        } else {
+            AddOutcome::Continue
+        }
     }
 
-    pub fn save<T: AsRef<Path>>(&self, path: T) -> Option<()> {
-        let mut file = OpenOptions::new()
-            .append(true)
-            .create(true)
-            .open(path)
-            .ok()?;
-        for data in &self.data {
-            assert_eq!(file.write(data.data()).ok()?, data.data().len());
+    pub fn verify(&self) -> Verification {
+        let Some(expected) = self.expected_sha256 else {
+            return Verification::NotProvided;
+        };
+        let got: [u8; 32] = self.hasher.clone().finalize().into();
+        if got == expected {
+            Verification::Match
+        } else {
+            Verification::Mismatch { expected, got }
         }
-        Some(())
     }
+
 }
 
 
 impl Requests {
-    pub fn to_header(&self) -> String {
-        match self {
-            Self::Full => "GET / HTTP/1.0\r\n\r\n".to_string(),
-            Self::Range(n, m) => format!("GET / HTTP/1.0\r\nRange: bytes={n}-{m}\r\n\r\n"),
+    pub fn to_header(self, key: Option<&str>) -> String {
+        let mut header = match self {
+            Self::Full => "GET / HTTP/1.0\r\n".to_string(),
+            Self::Range(range) => format!("GET / HTTP/1.0\r\nRange: {}\r\n", range.to_header_value()),
+        };
+        if let Some(key) = key {
+            header.push_str(AUTHORIZATION);
+            header.push_str(key);
+            header.push_str("\r\n");
         }
+        header.push_str("\r\n");
+        header
     }
 }
 
@@ -146,6 +449,43 @@ impl Headers {
                 .then(|| l.split_at(LENGTH.len()).1.parse().ok())?
         })
     }
+
+    pub fn content_sha256(&self) -> Option<[u8; 32]> {
+        self.all.lines().find_map(|l| {
+            l.starts_with(SHA256)
+                .then(|| Self::parse_hex32(l.split_at(SHA256.len()).1))?
+        })
+    }
+
+    pub fn content_range(&self) -> Option<(usize, usize, usize)> {
+        self.all
+            .lines()
+            .find_map(ContentRange::parse)
+            .map(|r| (r.start, r.end, r.total))
+    }
+
+    /// The HTTP status code from the response's status line (e.g. `200` or `206`).
+    pub fn status_code(&self) -> Option<u16> {
+        self.all.lines().next()?.split_whitespace().nth(1)?.parse().ok()
+    }
+
+    pub fn is_chunked(&self) -> bool {
+        self.all
+            .lines()
+            .any(|l| l.starts_with(TRANSFER_ENCODING) && l[TRANSFER_ENCODING.len()..].trim() == "chunked")
+    }
+
+    fn parse_hex32(hex: &str) -> Option<[u8; 32]> {
+        let hex = hex.trim();
+        if hex.len() != 64 {
+            return None;
+        }
+        let mut out = [0u8; 32];
+        for (i, byte) in out.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+        }
+        Some(out)
+    }
 }
 
 impl TryFrom<Vec<u8>> for Response {
@@ -155,11 +495,13 @@ impl TryFrom<Vec<u8>> for Response {
                 Ok(v) => Headers { all: v },
                 Err(_) => return Err(()),
             };
-            Ok(Self {
-                full_data: value,
-                headers_splitoff: pos + 4,
-                headers,
-            })
+            let raw_body = &value[pos + 4..];
+            let body = if headers.is_chunked() {
+                decode_chunked(raw_body).ok_or(())?
+            } else {
+                raw_body.to_vec()
+            };
+            Ok(Self { headers, body })
         } else {
             Err(())
         }
@@ -170,10 +512,33 @@ impl TryFrom<Vec<u8>> for Response {
 
 impl Response {
     pub fn data(&self) -> &[u8] {
-        &self.full_data[self.headers_splitoff..]
+        &self.body
     }
 }
 
+/// Decodes a `Transfer-Encoding: chunked` body: each chunk is a hex length on its own
+/// `\r\n`-terminated line, followed by exactly that many payload bytes and a trailing
+/// `\r\n`, terminating on a zero-length chunk (optionally followed by trailer headers).
+fn decode_chunked(mut bytes: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    loop {
+        let line_end = bytes.windows(2).position(|w| w == b"\r\n")?;
+        let size_line = std::str::from_utf8(&bytes[..line_end]).ok()?;
+        let size = usize::from_str_radix(size_line.split(';').next()?.trim(), 16).ok()?;
+        bytes = &bytes[line_end + 2..];
+
+        if size == 0 {
+            break;
+        }
+        if bytes.len() < size + 2 {
+            return None;
+        }
+        out.extend_from_slice(&bytes[..size]);
+        bytes = &bytes[size + 2..];
+    }
+    Some(out)
+}
+
 mod utils {
     use std::{env, io::Write, path::PathBuf};
 
@@ -232,6 +597,7 @@ mod utils {
     pub struct Args {
         pub address: String,
         pub output: PathBuf,
+        pub key: Option<String>,
     }
 
     pub fn parse_args() -> Args {
@@ -239,6 +605,7 @@ mod utils {
         let mut this = Args {
             address: String::from("127.0.0.1:8080"),
             output: PathBuf::from("data"),
+            key: None,
         };
 
         while let Some(arg) = args.next() {
@@ -251,9 +618,16 @@ mod utils {
                     let path = args.next().expect("Expected path after -o");
                     this.output = path.into();
                 }
+                "-k" => {
+                    let key = args.next().expect("Expected key after -k");
+                    if key.len() != 8 || !key.chars().all(|c| c.is_ascii_alphanumeric()) {
+                        panic!("Key must be 8 alphanumeric characters, got {key:?}");
+                    }
+                    this.key = Some(key);
+                }
                 "-h" => {
                     println!("Application that downloads the binary data from the glitchy server");
-                    println!("Usage: myftp [-a address] [-o output_path]");
+                    println!("Usage: myftp [-a address] [-o output_path] [-k key]");
                     std::process::exit(0);
                 }
                 _ => panic!("Unknown argument: {}", arg),